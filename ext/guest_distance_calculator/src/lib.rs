@@ -1,123 +1,711 @@
-use magnus::{function, prelude::*, Error, Ruby, Float};
+use magnus::{function, prelude::*, Error, Ruby, Float, Proc, Value};
 use lazy_static::lazy_static;
+use rand::Rng;
+use rayon::prelude::*;
 use serde::Serialize;
 use std::collections::{HashSet, HashMap};
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 use std::cmp::Ordering;
 
 const TRESHOLD: f64 = 2.0;
 const MATCHES_LIMIT: usize = 20;
 
+// Selectable distance metric, mirroring the `HnswDistance` choice pattern.
+// Each variant has its own default threshold below since Manhattan, Euclidean
+// and cosine distances don't live on the same scale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DistanceMetric {
+    Manhattan,
+    Euclidean,
+    Cosine,
+}
+
+impl DistanceMetric {
+    fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "manhattan" => Ok(DistanceMetric::Manhattan),
+            "euclidean" => Ok(DistanceMetric::Euclidean),
+            "cosine" => Ok(DistanceMetric::Cosine),
+            _ => Err(Error::new(
+                magnus::exception::arg_error(),
+                format!("unknown distance metric: {}", name),
+            )),
+        }
+    }
+
+    fn default_threshold(&self) -> f64 {
+        match self {
+            DistanceMetric::Manhattan => TRESHOLD,
+            DistanceMetric::Euclidean => 3.0,
+            DistanceMetric::Cosine => 0.5,
+        }
+    }
+}
+
+// How a pair is scored when one guest has a score for a thematic and the
+// other doesn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MissingStrategy {
+    SharedOnly,
+    ImputeMean,
+    Penalize,
+}
+
+impl MissingStrategy {
+    fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "shared_only" => Ok(MissingStrategy::SharedOnly),
+            "impute_mean" => Ok(MissingStrategy::ImputeMean),
+            "penalize" => Ok(MissingStrategy::Penalize),
+            _ => Err(Error::new(
+                magnus::exception::arg_error(),
+                format!("unknown missing score strategy: {}", name),
+            )),
+        }
+    }
+}
+
+// Fixed per-term contribution a one-sided missing score gets under `Penalize`.
+const MISSING_PENALTY_DISTANCE: f64 = 1.0;
+
+// HNSW tuning knobs, named after the paper (Malkov & Yashunin).
+const HNSW_M: usize = 16;
+const HNSW_MAX_M0: usize = 32;
+const HNSW_EF_CONSTRUCTION: usize = 100;
+const HNSW_MISSING_SCORE_DEFAULT: f64 = 0.0;
+
+// How many slice guests `calculate_distances` processes before checking back
+// in with the caller's progress callback.
+const PROGRESS_CHUNK_SIZE: usize = 200;
+
 #[derive(Debug)]
 pub struct GuestDistanceCalculator {
-    data: Mutex<HashMap<String, HashMap<String, f64>>>, // guest_id -> thematic_id -> score
-    thematic_ids: Mutex<HashSet<String>>,               // Set to store unique thematic IDs
-    other_guest_ids: Mutex<HashSet<String>>,            // Set to store other guest IDs
-    thematics_count: Mutex<usize>,                      // Counter for total unique thematics
+    data: RwLock<HashMap<String, HashMap<String, f64>>>, // guest_id -> thematic_id -> score
+    thematic_ids: RwLock<HashSet<String>>,               // Set to store unique thematic IDs
+    other_guest_ids: RwLock<HashSet<String>>,            // Set to store other guest IDs
+    thematics_count: Mutex<usize>,                       // Counter for total unique thematics
+    index: Mutex<Option<HnswIndex>>,                     // Lazily (re)built approximate nn index
+    metric: Mutex<DistanceMetric>,                        // Active distance metric
+    metric_thresholds: Mutex<HashMap<DistanceMetric, f64>>, // Per-metric threshold overrides
+    weight: RwLock<HashMap<String, f64>>,                 // thematic_id -> weight, default 1.0
+    matches_limit: Mutex<usize>,                          // Runtime override of MATCHES_LIMIT
+    missing_strategy: Mutex<MissingStrategy>,             // How to treat one-sided missing scores
+    thematic_means: RwLock<HashMap<String, f64>>,         // Cache for ImputeMean, keyed by thematic_id
 }
 
 impl GuestDistanceCalculator {
     pub fn new() -> Self {
         Self {
-            data: Mutex::new(HashMap::new()),
-            thematic_ids: Mutex::new(HashSet::new()),
-            other_guest_ids: Mutex::new(HashSet::new()),
+            data: RwLock::new(HashMap::new()),
+            thematic_ids: RwLock::new(HashSet::new()),
+            other_guest_ids: RwLock::new(HashSet::new()),
             thematics_count: Mutex::new(0),
+            index: Mutex::new(None),
+            metric: Mutex::new(DistanceMetric::Manhattan),
+            metric_thresholds: Mutex::new(HashMap::new()),
+            weight: RwLock::new(HashMap::new()),
+            matches_limit: Mutex::new(MATCHES_LIMIT),
+            missing_strategy: Mutex::new(MissingStrategy::SharedOnly),
+            thematic_means: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_missing_strategy(&self, name: String) -> Result<(), Error> {
+        *self.missing_strategy.lock().unwrap() = MissingStrategy::from_name(&name)?;
+        Ok(())
+    }
+
+    // Mean score for `thematic_id` across every guest that has one, cached
+    // until the next `insert_score`/`clear` invalidates it.
+    fn thematic_mean(&self, thematic_id: &str) -> f64 {
+        if let Some(mean) = self.thematic_means.read().unwrap().get(thematic_id) {
+            return *mean;
+        }
+
+        let data = self.data.read().unwrap();
+        let scores: Vec<f64> = data.values().filter_map(|m| m.get(thematic_id).copied()).collect();
+        let mean = if scores.is_empty() {
+            0.0
+        } else {
+            scores.iter().sum::<f64>() / scores.len() as f64
+        };
+
+        self.thematic_means.write().unwrap().insert(thematic_id.to_string(), mean);
+        mean
+    }
+
+    // Resolves what (score_a, score_b) pair a thematic contributes to the
+    // distance, or None if it should be skipped entirely, according to the
+    // active `MissingStrategy`.
+    fn resolve_pair(
+        &self,
+        thematic_id: &str,
+        score_a: Option<f64>,
+        score_b: Option<f64>,
+        strategy: MissingStrategy,
+    ) -> Option<(f64, f64)> {
+        match (score_a, score_b) {
+            (Some(a), Some(b)) => Some((a, b)),
+            (Some(a), None) => match strategy {
+                MissingStrategy::SharedOnly => None,
+                MissingStrategy::ImputeMean => Some((a, self.thematic_mean(thematic_id))),
+                MissingStrategy::Penalize => Some((a, a + MISSING_PENALTY_DISTANCE)),
+            },
+            (None, Some(b)) => match strategy {
+                MissingStrategy::SharedOnly => None,
+                MissingStrategy::ImputeMean => Some((self.thematic_mean(thematic_id), b)),
+                MissingStrategy::Penalize => Some((b + MISSING_PENALTY_DISTANCE, b)),
+            },
+            (None, None) => None,
         }
     }
 
+    pub fn set_metric(&self, name: String) -> Result<(), Error> {
+        *self.metric.lock().unwrap() = DistanceMetric::from_name(&name)?;
+        Ok(())
+    }
+
+    pub fn insert_thematic_weights(&self, weights: HashMap<String, f64>) {
+        self.weight.write().unwrap().extend(weights);
+    }
+
+    fn weight_for(&self, thematic_id: &str) -> f64 {
+        self.weight.read().unwrap().get(thematic_id).copied().unwrap_or(1.0)
+    }
+
+    pub fn set_threshold(&self, metric_name: String, value: f64) -> Result<(), Error> {
+        let metric = DistanceMetric::from_name(&metric_name)?;
+        self.metric_thresholds.lock().unwrap().insert(metric, value);
+        Ok(())
+    }
+
+    pub fn set_matches_limit(&self, limit: usize) {
+        *self.matches_limit.lock().unwrap() = limit;
+    }
+
     pub fn insert_score(&self, guest_id: String, thematic_id: String, score: f64) {
-        let mut data = self.data.lock().unwrap();
+        let mut data = self.data.write().unwrap();
         data.entry(guest_id)
             .or_insert_with(HashMap::new)
             .insert(thematic_id, score);
+
+        // The score vectors changed under it, so the index and cached thematic
+        // means are stale.
+        *self.index.lock().unwrap() = None;
+        self.thematic_means.write().unwrap().clear();
     }
 
     pub fn insert_thematic_ids(&self, ids: Vec<String>) {
-        let mut thematic_ids = self.thematic_ids.lock().unwrap();
+        let mut thematic_ids = self.thematic_ids.write().unwrap();
         let mut thematics_count = self.thematics_count.lock().unwrap();
         for id in ids {
             if thematic_ids.insert(id) {
                 *thematics_count += 1;
             }
         }
+
+        // A new thematic changes the vector ordering/dimensionality the index
+        // was built against, so it's stale.
+        *self.index.lock().unwrap() = None;
     }
 
     pub fn insert_other_guest_ids(&self, ids: Vec<String>) {
-        let mut other_guest_ids = self.other_guest_ids.lock().unwrap();
+        let mut other_guest_ids = self.other_guest_ids.write().unwrap();
         for id in ids {
             other_guest_ids.insert(id);
         }
     }
 
     pub fn get_score(&self, guest_id: String, thematic_id: String) -> Option<f64> {
-        let data = self.data.lock().unwrap();
+        let data = self.data.read().unwrap();
         data.get(&guest_id)
             .and_then(|thematic_map| thematic_map.get(&thematic_id).copied())
     }
 
     pub fn calculate_total_distance(&self, guest_a_id: String, guest_b_id: String) -> f64 {
-        let thematic_ids = self.thematic_ids.lock().unwrap();
-        let mut total_distance = 0.0;
-        let thematics_count = *self.thematics_count.lock().unwrap();
-
-        for thematic_id in thematic_ids.iter() {
-            let scoring_g1 = self.get_score(guest_a_id.clone(), thematic_id.clone());
-            let scoring_g2 = self.get_score(guest_b_id.clone(), thematic_id.clone());
+        let metric = *self.metric.lock().unwrap();
+        let strategy = *self.missing_strategy.lock().unwrap();
+        self.calculate_total_distance_with(guest_a_id, guest_b_id, metric, strategy)
+    }
 
-            if let (Some(score1), Some(score2)) = (scoring_g1, scoring_g2) {
-                total_distance += (score1 - score2).abs();
+    // Same as `calculate_total_distance`, but takes the metric/strategy as
+    // arguments instead of locking them, so a caller that already snapshotted
+    // them once (e.g. a parallel `calculate_distances` run) isn't re-locking
+    // per pair.
+    fn calculate_total_distance_with(
+        &self,
+        guest_a_id: String,
+        guest_b_id: String,
+        metric: DistanceMetric,
+        strategy: MissingStrategy,
+    ) -> f64 {
+        let thematic_ids = self.thematic_ids.read().unwrap();
+
+        let shared_scores: Vec<(&String, f64, f64)> = thematic_ids
+            .iter()
+            .filter_map(|thematic_id| {
+                let scoring_g1 = self.get_score(guest_a_id.clone(), thematic_id.clone());
+                let scoring_g2 = self.get_score(guest_b_id.clone(), thematic_id.clone());
+                self.resolve_pair(thematic_id, scoring_g1, scoring_g2, strategy)
+                    .map(|(s1, s2)| (thematic_id, s1, s2))
+            })
+            .collect();
+
+        match metric {
+            DistanceMetric::Manhattan => {
+                let weighted_distance: f64 = shared_scores
+                    .iter()
+                    .map(|(thematic_id, a, b)| self.weight_for(thematic_id) * (a - b).abs())
+                    .sum();
+                let weight_sum: f64 = shared_scores
+                    .iter()
+                    .map(|(thematic_id, _, _)| self.weight_for(thematic_id))
+                    .sum();
+
+                if weight_sum > 0.0 {
+                    weighted_distance / weight_sum
+                } else {
+                    0.0
+                }
+            }
+            DistanceMetric::Euclidean => {
+                let weight_sum: f64 = shared_scores
+                    .iter()
+                    .map(|(thematic_id, _, _)| self.weight_for(thematic_id))
+                    .sum();
+
+                if weight_sum > 0.0 {
+                    // Weighted RMS over the shared thematics, so the result
+                    // stays on a comparable scale regardless of how many
+                    // thematics two guests happen to overlap on (mirrors how
+                    // Manhattan divides by the shared-weight sum).
+                    let weighted_mean_squared: f64 = shared_scores
+                        .iter()
+                        .map(|(thematic_id, a, b)| self.weight_for(thematic_id) * (a - b) * (a - b))
+                        .sum::<f64>()
+                        / weight_sum;
+                    weighted_mean_squared.sqrt()
+                } else {
+                    0.0
+                }
+            }
+            DistanceMetric::Cosine => {
+                let dot: f64 = shared_scores
+                    .iter()
+                    .map(|(thematic_id, a, b)| self.weight_for(thematic_id) * a * b)
+                    .sum();
+                let norm_a = shared_scores
+                    .iter()
+                    .map(|(thematic_id, a, _)| self.weight_for(thematic_id) * a * a)
+                    .sum::<f64>()
+                    .sqrt();
+                let norm_b = shared_scores
+                    .iter()
+                    .map(|(thematic_id, _, b)| self.weight_for(thematic_id) * b * b)
+                    .sum::<f64>()
+                    .sqrt();
+                if norm_a == 0.0 || norm_b == 0.0 {
+                    0.0
+                } else {
+                    1.0 - dot / (norm_a * norm_b)
+                }
             }
         }
+    }
 
-        if thematics_count > 0 {
-            total_distance / thematics_count as f64
-        } else {
-            0.0
-        }
+    fn threshold_for(&self, metric: DistanceMetric) -> f64 {
+        self.metric_thresholds
+            .lock()
+            .unwrap()
+            .get(&metric)
+            .copied()
+            .unwrap_or_else(|| metric.default_threshold())
     }
 
     pub fn sum_distances_on_all_thematics(&self, guest_a_id: String, guest_b_id: String) -> Option<TempDistance> {
-        let total_distance = self.calculate_total_distance(guest_a_id.clone(), guest_b_id.clone());
+        let metric = *self.metric.lock().unwrap();
+        let strategy = *self.missing_strategy.lock().unwrap();
+        let threshold = self.threshold_for(metric);
+        self.sum_distances_on_all_thematics_with(guest_a_id, guest_b_id, metric, strategy, threshold)
+    }
 
-        if total_distance > TRESHOLD {
+    // Same as `sum_distances_on_all_thematics`, but takes the metric/strategy/
+    // threshold as arguments instead of locking them per call.
+    fn sum_distances_on_all_thematics_with(
+        &self,
+        guest_a_id: String,
+        guest_b_id: String,
+        metric: DistanceMetric,
+        strategy: MissingStrategy,
+        threshold: f64,
+    ) -> Option<TempDistance> {
+        let total_distance =
+            self.calculate_total_distance_with(guest_a_id.clone(), guest_b_id.clone(), metric, strategy);
+
+        if total_distance > threshold {
             None
         } else {
             Some(TempDistance::new(guest_a_id, guest_b_id, total_distance))
         }
     }
 
-		pub fn calculate_distances(&self, guests_slice_ids: Vec<String>) -> Vec<TempDistance> {
-				let other_guest_ids = self.other_guest_ids.lock().unwrap();
-
-				// Collect distances for each g1_id, sort and truncate, then accumulate the results
-				let all_distances = guests_slice_ids.iter()
-						.flat_map(|g1_id| {
-								let mut distances = other_guest_ids.iter()
-										.filter_map(|g2_id| self.sum_distances_on_all_thematics(g1_id.clone(), g2_id.clone()))
-										.collect::<Vec<TempDistance>>();
-
-								// Sort and truncate this group for the current g1_id
-								distances.sort();
-								distances.truncate(MATCHES_LIMIT);
-
-								distances.into_iter() // Return this group's distances for further accumulation
-						})
-						.collect::<Vec<TempDistance>>();
+		pub fn calculate_distances(
+				&self,
+				guests_slice_ids: Vec<String>,
+				thread_count: Option<usize>,
+				mut status_callback: Option<impl FnMut(usize, usize)>,
+		) -> Vec<TempDistance> {
+				// Snapshot the ids we're matching against, and the settings that
+				// drive each pair comparison, once up front. Each worker then reads
+				// from owned values instead of re-locking `metric`/`missing_strategy`/
+				// `metric_thresholds` for every single pair.
+				let other_guest_ids: Vec<String> =
+						self.other_guest_ids.read().unwrap().iter().cloned().collect();
+				let metric = *self.metric.lock().unwrap();
+				let strategy = *self.missing_strategy.lock().unwrap();
+				let threshold = self.threshold_for(metric);
+				let matches_limit = *self.matches_limit.lock().unwrap();
+
+				let pool = thread_count
+						.map(|n| rayon::ThreadPoolBuilder::new().num_threads(n).build().unwrap());
+
+				let total = guests_slice_ids.len();
+				let mut all_distances = Vec::new();
+				let mut done = 0usize;
+
+				// The callback is a Ruby proc, which can only be called safely from
+				// the thread that's holding the GVL. So rather than polling an
+				// atomic counter from a side thread, we process the slice in chunks
+				// on the calling thread and report progress between chunks.
+				for chunk in guests_slice_ids.chunks(PROGRESS_CHUNK_SIZE) {
+						let run = || {
+								chunk.par_iter()
+										.flat_map(|g1_id| {
+												let mut distances = other_guest_ids.iter()
+														.filter_map(|g2_id| self.sum_distances_on_all_thematics_with(
+																g1_id.clone(), g2_id.clone(), metric, strategy, threshold,
+														))
+														.collect::<Vec<TempDistance>>();
+
+												// Sort and truncate this group for the current g1_id
+												distances.sort();
+												distances.truncate(matches_limit);
+
+												distances.into_par_iter() // Return this group's distances for further accumulation
+										})
+										.collect::<Vec<TempDistance>>()
+						};
+
+						let chunk_distances = match &pool {
+								Some(pool) => pool.install(run),
+								None => run(),
+						};
+						all_distances.extend(chunk_distances);
+
+						done += chunk.len();
+						if let Some(callback) = status_callback.as_mut() {
+								callback(done, total);
+						}
+				}
 
 				all_distances
 		}
 
+    // Full symmetric distance matrix for `guest_ids`, with no threshold cutoff
+    // and no per-guest truncation. Each unordered pair is computed once; set
+    // `upper_triangle_only` to skip emitting the mirrored (b, a) entry.
+    pub fn distance_matrix(&self, guest_ids: Vec<String>, upper_triangle_only: bool) -> Vec<TempDistance> {
+        let mut matrix = Vec::new();
+
+        for (i, guest_a_id) in guest_ids.iter().enumerate() {
+            for guest_b_id in guest_ids.iter().skip(i + 1) {
+                let distance = self.calculate_total_distance(guest_a_id.clone(), guest_b_id.clone());
+                matrix.push(TempDistance::new(guest_a_id.clone(), guest_b_id.clone(), distance));
+                if !upper_triangle_only {
+                    matrix.push(TempDistance::new(guest_b_id.clone(), guest_a_id.clone(), distance));
+                }
+            }
+        }
+
+        matrix
+    }
+
     pub fn clear(&self) {
-        let mut data = self.data.lock().unwrap();
-        let mut thematic_ids = self.thematic_ids.lock().unwrap();
-        let mut other_guest_ids = self.other_guest_ids.lock().unwrap();
+        let mut data = self.data.write().unwrap();
+        let mut thematic_ids = self.thematic_ids.write().unwrap();
+        let mut other_guest_ids = self.other_guest_ids.write().unwrap();
         let mut thematics_count = self.thematics_count.lock().unwrap();
 
         data.clear();
         thematic_ids.clear();
         other_guest_ids.clear();
         *thematics_count = 0;
+        *self.index.lock().unwrap() = None;
+        self.thematic_means.write().unwrap().clear();
+    }
+
+    // Dense per-guest vector indexed by a stable (sorted) ordering of thematic_ids.
+    // Guests missing a score for a thematic get `HNSW_MISSING_SCORE_DEFAULT`.
+    fn vector_for(&self, guest_id: &str, thematic_order: &[String]) -> Vec<f64> {
+        let data = self.data.read().unwrap();
+        let scores = data.get(guest_id);
+        thematic_order
+            .iter()
+            .map(|t| {
+                scores
+                    .and_then(|m| m.get(t).copied())
+                    .unwrap_or(HNSW_MISSING_SCORE_DEFAULT)
+            })
+            .collect()
+    }
+
+    fn build_index(&self) -> HnswIndex {
+        let thematic_order: Vec<String> = {
+            let thematic_ids = self.thematic_ids.read().unwrap();
+            let mut ids: Vec<String> = thematic_ids.iter().cloned().collect();
+            ids.sort();
+            ids
+        };
+
+        let guest_ids: Vec<String> = {
+            let data = self.data.read().unwrap();
+            data.keys().cloned().collect()
+        };
+
+        let mut index = HnswIndex::new();
+        for guest_id in guest_ids {
+            let vector = self.vector_for(&guest_id, &thematic_order);
+            index.insert(guest_id, vector);
+        }
+        index
+    }
+
+    // Returns the approximate `k` closest guests to `guest_id`, rebuilding the
+    // HNSW index first if it was invalidated by a write since the last build.
+    pub fn nearest_matches(&self, guest_id: String, k: usize) -> Vec<TempDistance> {
+        let mut index_guard = self.index.lock().unwrap();
+        if index_guard.is_none() {
+            *index_guard = Some(self.build_index());
+        }
+        let index = index_guard.as_ref().unwrap();
+
+        index
+            .search(&guest_id, k)
+            .into_iter()
+            .map(|(other_id, distance)| TempDistance::new(guest_id.clone(), other_id, distance))
+            .collect()
+    }
+}
+
+// A node's neighbor lists, one `Vec<String>` per layer it participates in (layer 0 first).
+#[derive(Debug, Clone)]
+struct HnswNode {
+    vector: Vec<f64>,
+    neighbors: Vec<Vec<String>>,
+}
+
+// Approximate nearest-neighbor index over guest score vectors, built with the
+// standard Hierarchical Navigable Small World construction (Malkov & Yashunin).
+#[derive(Debug)]
+struct HnswIndex {
+    nodes: HashMap<String, HnswNode>,
+    entry_point: Option<String>,
+}
+
+impl HnswIndex {
+    fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            entry_point: None,
+        }
+    }
+
+    fn distance(a: &[f64], b: &[f64]) -> f64 {
+        a.iter()
+            .zip(b.iter())
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    fn random_level() -> usize {
+        let m_l = 1.0 / (HNSW_M as f64).ln();
+        let r: f64 = rand::thread_rng().gen_range(f64::EPSILON..1.0);
+        (-r.ln() * m_l).floor() as usize
+    }
+
+    fn max_level(&self) -> usize {
+        self.entry_point
+            .as_ref()
+            .map(|ep| self.nodes[ep].neighbors.len() - 1)
+            .unwrap_or(0)
+    }
+
+    // Greedy descent from `from` towards `query`, staying on `layer`.
+    fn greedy_closest(&self, from: &str, query: &[f64], layer: usize) -> String {
+        let mut current = from.to_string();
+        let mut current_distance = Self::distance(&self.nodes[&current].vector, query);
+
+        loop {
+            let mut improved = false;
+            if let Some(neighbors) = self.nodes[&current].neighbors.get(layer) {
+                for neighbor in neighbors {
+                    let d = Self::distance(&self.nodes[neighbor].vector, query);
+                    if d < current_distance {
+                        current_distance = d;
+                        current = neighbor.clone();
+                        improved = true;
+                    }
+                }
+            }
+            if !improved {
+                return current;
+            }
+        }
+    }
+
+    // Beam search on a single layer, returning up to `ef` closest candidates
+    // (guest_id, distance), sorted closest-first.
+    fn search_layer(&self, entry: &str, query: &[f64], layer: usize, ef: usize) -> Vec<(String, f64)> {
+        let mut visited: HashSet<String> = HashSet::new();
+        visited.insert(entry.to_string());
+
+        let entry_distance = Self::distance(&self.nodes[entry].vector, query);
+        let mut candidates: Vec<(String, f64)> = vec![(entry.to_string(), entry_distance)];
+        let mut found: Vec<(String, f64)> = vec![(entry.to_string(), entry_distance)];
+
+        while let Some(pos) = candidates
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1 .1.partial_cmp(&b.1 .1).unwrap_or(Ordering::Equal))
+            .map(|(i, _)| i)
+        {
+            let (current_id, current_distance) = candidates.remove(pos);
+
+            let worst_found = found
+                .iter()
+                .map(|(_, d)| *d)
+                .fold(f64::MIN, f64::max);
+            if found.len() >= ef && current_distance > worst_found {
+                break;
+            }
+
+            if let Some(neighbors) = self.nodes[&current_id].neighbors.get(layer) {
+                for neighbor in neighbors {
+                    if visited.insert(neighbor.clone()) {
+                        let d = Self::distance(&self.nodes[neighbor].vector, query);
+                        candidates.push((neighbor.clone(), d));
+                        found.push((neighbor.clone(), d));
+                    }
+                }
+            }
+        }
+
+        found.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+        found.truncate(ef.max(1));
+        found
+    }
+
+    fn insert(&mut self, guest_id: String, vector: Vec<f64>) {
+        let level = Self::random_level();
+
+        let entry_point = match self.entry_point.clone() {
+            None => {
+                self.nodes.insert(
+                    guest_id.clone(),
+                    HnswNode {
+                        vector,
+                        neighbors: vec![Vec::new(); level + 1],
+                    },
+                );
+                self.entry_point = Some(guest_id);
+                return;
+            }
+            Some(ep) => ep,
+        };
+
+        let top_layer = self.max_level();
+        let mut nearest = entry_point;
+        for layer in (level + 1..=top_layer).rev() {
+            nearest = self.greedy_closest(&nearest, &vector, layer);
+        }
+
+        self.nodes.insert(
+            guest_id.clone(),
+            HnswNode {
+                vector: vector.clone(),
+                neighbors: vec![Vec::new(); level + 1],
+            },
+        );
+
+        for layer in (0..=level.min(top_layer)).rev() {
+            let candidates = self.search_layer(&nearest, &vector, layer, HNSW_EF_CONSTRUCTION);
+            let m = if layer == 0 { HNSW_MAX_M0 } else { HNSW_M };
+
+            let selected: Vec<String> = candidates
+                .iter()
+                .filter(|(id, _)| id != &guest_id)
+                .take(m)
+                .map(|(id, _)| id.clone())
+                .collect();
+
+            if let Some(first) = selected.first() {
+                nearest = first.clone();
+            }
+
+            for neighbor_id in &selected {
+                self.nodes.get_mut(&guest_id).unwrap().neighbors[layer].push(neighbor_id.clone());
+
+                let neighbor_node = self.nodes.get_mut(neighbor_id).unwrap();
+                if neighbor_id == &guest_id || neighbor_node.neighbors.len() <= layer {
+                    continue;
+                }
+                neighbor_node.neighbors[layer].push(guest_id.clone());
+
+                // Prune back to `m` neighbors, keeping the closest ones.
+                if neighbor_node.neighbors[layer].len() > m {
+                    let neighbor_vector = neighbor_node.vector.clone();
+                    let links = neighbor_node.neighbors[layer].clone();
+                    let mut ranked: Vec<(String, f64)> = links
+                        .iter()
+                        .map(|id| (id.clone(), Self::distance(&self.nodes[id].vector, &neighbor_vector)))
+                        .collect();
+                    ranked.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+                    ranked.truncate(m);
+                    self.nodes.get_mut(neighbor_id).unwrap().neighbors[layer] =
+                        ranked.into_iter().map(|(id, _)| id).collect();
+                }
+            }
+        }
+
+        if level > top_layer {
+            self.entry_point = Some(guest_id);
+        }
+    }
+
+    fn search(&self, guest_id: &str, k: usize) -> Vec<(String, f64)> {
+        let entry_point = match &self.entry_point {
+            None => return Vec::new(),
+            Some(ep) => ep.clone(),
+        };
+        let query = match self.nodes.get(guest_id) {
+            Some(node) => node.vector.clone(),
+            None => return Vec::new(),
+        };
+
+        let top_layer = self.max_level();
+        let mut nearest = entry_point;
+        for layer in (1..=top_layer).rev() {
+            nearest = self.greedy_closest(&nearest, &query, layer);
+        }
+
+        // One extra slot, since the query guest itself is found and dropped below.
+        let ef = k + 1;
+        self.search_layer(&nearest, &query, 0, ef)
+            .into_iter()
+            .filter(|(id, _)| id != guest_id)
+            .take(k)
+            .collect()
     }
 }
 
@@ -154,6 +742,10 @@ impl Ord for Distance {
 
 impl Eq for Distance {}
 
+// `calculate_distances` and `nearest_matches` both hand back distance pairs
+// under this name; it is the same shape as `Distance`.
+pub type TempDistance = Distance;
+
 // Initialize a global static instance of GuestDistanceCalculator, wrapped in a Mutex
 lazy_static! {
     static ref CALCULATOR: GuestDistanceCalculator = GuestDistanceCalculator::new();
@@ -174,18 +766,68 @@ fn insert_other_guest_ids(ids: Vec<String>) {
     CALCULATOR.insert_other_guest_ids(ids);
 }
 
-// Function to calculate distances, converting each TempDistance to a Ruby-compatible hash
-fn calculate_distances(guests_slice_ids: Vec<String>) -> String {
-    let distances = CALCULATOR.calculate_distances(guests_slice_ids);
+// Function to select the active distance metric ("manhattan", "euclidean" or "cosine"), callable from Ruby
+fn set_metric(name: String) -> Result<(), Error> {
+    CALCULATOR.set_metric(name)
+}
+
+// Function to set per-thematic weights, callable from Ruby
+fn insert_thematic_weights(weights: HashMap<String, f64>) {
+    CALCULATOR.insert_thematic_weights(weights);
+}
+
+// Function to override a metric's threshold, callable from Ruby
+fn set_threshold(metric_name: String, value: f64) -> Result<(), Error> {
+    CALCULATOR.set_threshold(metric_name, value)
+}
+
+// Function to override how many matches calculate_distances keeps per guest, callable from Ruby
+fn set_matches_limit(limit: usize) {
+    CALCULATOR.set_matches_limit(limit);
+}
+
+// Function to select how one-sided missing scores are handled
+// ("shared_only", "impute_mean" or "penalize"), callable from Ruby
+fn set_missing_strategy(name: String) -> Result<(), Error> {
+    CALCULATOR.set_missing_strategy(name)
+}
+
+// Function to calculate distances, converting each TempDistance to a Ruby-compatible hash.
+// All three arguments must be passed from Ruby; `thread_count` may be nil, in which case
+// rayon's default global pool size is used.
+// `status_callback`, when not nil, is called as `callback.(done, total)` after each chunk.
+fn calculate_distances(
+    guests_slice_ids: Vec<String>,
+    thread_count: Option<usize>,
+    status_callback: Option<Proc>,
+) -> String {
+    let callback = status_callback.map(|callback| {
+        move |done: usize, total: usize| {
+            let _: Result<Value, Error> = callback.call((done, total));
+        }
+    });
+    let distances = CALCULATOR.calculate_distances(guests_slice_ids, thread_count, callback);
     serde_json::to_string(&distances).unwrap() // Convert the distances to JSON string
 }
 
+// Function to compute the full (or upper-triangle) distance matrix for a set of guests, callable from Ruby
+fn distance_matrix(guest_ids: Vec<String>, upper_triangle_only: bool) -> String {
+    let matrix = CALCULATOR.distance_matrix(guest_ids, upper_triangle_only);
+    serde_json::to_string(&matrix).unwrap()
+}
+
 
 // Function to clear the cache, callable from Ruby
 fn clear() {
     CALCULATOR.clear();
 }
 
+// Function to look up the approximate k nearest guests via the HNSW index, callable from Ruby
+fn nearest_matches(guest_id: String, k: usize) -> String {
+    let matches = CALCULATOR.nearest_matches(guest_id, k);
+    serde_json::to_string(&matches).unwrap()
+}
+
 // Initialization function to define the Ruby module and expose methods
 #[magnus::init]
 fn init(ruby: &Ruby) -> Result<(), Error> {
@@ -193,7 +835,14 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
     module.define_singleton_method("insert_score", function!(insert_score, 3))?;
     module.define_singleton_method("insert_thematic_ids", function!(insert_thematic_ids, 1))?;
     module.define_singleton_method("insert_other_guest_ids", function!(insert_other_guest_ids, 1))?;
-    module.define_singleton_method("calculate_distances", function!(calculate_distances, 1))?;
+    module.define_singleton_method("set_metric", function!(set_metric, 1))?;
+    module.define_singleton_method("insert_thematic_weights", function!(insert_thematic_weights, 1))?;
+    module.define_singleton_method("set_threshold", function!(set_threshold, 2))?;
+    module.define_singleton_method("set_matches_limit", function!(set_matches_limit, 1))?;
+    module.define_singleton_method("set_missing_strategy", function!(set_missing_strategy, 1))?;
+    module.define_singleton_method("calculate_distances", function!(calculate_distances, 3))?;
+    module.define_singleton_method("nearest_matches", function!(nearest_matches, 2))?;
+    module.define_singleton_method("distance_matrix", function!(distance_matrix, 2))?;
     module.define_singleton_method("clear", function!(clear, 0))?;
     Ok(())
 }